@@ -3,14 +3,24 @@
 #![no_std]
 #![no_main]
 
+use bas_peripheral_util as util;
 use embassy_executor::Spawner;
-use embassy_futures::{join::join, select::select};
-use embassy_time::Timer;
+use embassy_futures::{
+    join::{join, join_array},
+    select::select,
+};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer};
 use esp_alloc as _;
 use esp_backtrace as _;
 #[cfg(target_arch = "riscv32")]
 use esp_hal::interrupt::software::SoftwareInterruptControl;
-use esp_hal::{clock::CpuClock, timer::timg::TimerGroup};
+use esp_hal::{
+    clock::CpuClock,
+    efuse::Efuse,
+    peripherals::TIMG0,
+    timer::timg::{MwdtStage, TimerGroup, Wdt},
+};
 use esp_radio::ble::controller::BleConnector;
 use log::{info, warn};
 use static_cell::StaticCell;
@@ -19,10 +29,10 @@ use trouble_host::prelude::*;
 esp_bootloader_esp_idf::esp_app_desc!();
 
 #[esp_rtos::main]
-async fn main(_s: Spawner) {
+async fn main(spawner: Spawner) {
     esp_println::logger::init_logger_from_env();
     let peripherals = esp_hal::init(esp_hal::Config::default().with_cpu_clock(CpuClock::max()));
-    esp_alloc::heap_allocator!(size: 72 * 1024);
+    esp_alloc::heap_allocator!(size: HEAP_SIZE);
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     #[cfg(target_arch = "riscv32")]
     let sw_int = SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
@@ -31,6 +41,7 @@ async fn main(_s: Spawner) {
         #[cfg(target_arch = "riscv32")]
         sw_int.software_interrupt0,
     );
+    spawner.spawn(watchdog_task(timg0.wdt)).ok();
 
     static RADIO: StaticCell<esp_radio::Controller<'static>> = StaticCell::new();
     let radio = RADIO.init(esp_radio::init().unwrap());
@@ -39,7 +50,19 @@ async fn main(_s: Spawner) {
     let connector = BleConnector::new(radio, bluetooth, Default::default()).unwrap();
     let controller: ExternalController<_, 20> = ExternalController::new(connector);
 
-    ble_bas_peripheral_run(controller).await;
+    // `cfg!` rather than `#[cfg]` keeps both calls building whatever the features, so every build
+    // of the example also checks the stack instantiated for two simultaneous connections.
+    if cfg!(feature = "two-connections") {
+        ble_bas_peripheral_run::<_, 2, 4>(controller, NAME, APPEARANCE, CONNECTION_PARAMS).await;
+    } else {
+        ble_bas_peripheral_run::<_, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX>(
+            controller,
+            NAME,
+            APPEARANCE,
+            CONNECTION_PARAMS,
+        )
+        .await;
+    }
 }
 
 /// Max number of connections
@@ -47,6 +70,39 @@ const CONNECTIONS_MAX: usize = 1;
 /// Max number of L2CAP channels.
 const L2CAP_CHANNELS_MAX: usize = 2; // Signal + att
 
+/// Size of the heap, in bytes.
+const HEAP_SIZE: usize = 72 * 1024;
+/// Heap that should still be free once the radio is initialized, in bytes.
+///
+/// This is a margin rather than a measurement. Raise it together with [`HEAP_SIZE`] when the
+/// application allocates more.
+const MIN_FREE_HEAP: usize = 16 * 1024;
+
+/// Firmware version advertised in the manufacturer specific data, as major, minor and patch.
+///
+/// This is the version of the example's package, which is a placeholder 0.0.0. Set the version of
+/// your own package to advertise something meaningful.
+const FIRMWARE_VERSION: [u8; 3] = [
+    util::version_component(env!("CARGO_PKG_VERSION_MAJOR")),
+    util::version_component(env!("CARGO_PKG_VERSION_MINOR")),
+    util::version_component(env!("CARGO_PKG_VERSION_PATCH")),
+];
+
+/// Name of the device, advertised in the scan response and used as the GAP device name.
+const NAME: &str = "Trouble Example";
+/// GAP appearance of the device.
+const APPEARANCE: &BluetoothUuid16 = &appearance::power_device::GENERIC_POWER_DEVICE;
+
+/// Connection parameters requested once a central connects. A short connection interval lowers
+/// the latency and raises the throughput compared to the defaults of most centrals. The
+/// supervision timeout decides how soon a central that went out of range is noticed.
+const CONNECTION_PARAMS: util::ConnectionParams = util::ConnectionParams {
+    min_interval_us: 7_500,
+    max_interval_us: 15_000,
+    max_latency: 0,
+    supervision_timeout_ms: 4_000,
+};
+
 // GATT Server definition
 #[gatt_server]
 struct Server {
@@ -66,50 +122,155 @@ struct BatteryService {
 }
 
 /// Run the BLE stack.
-pub async fn ble_bas_peripheral_run<C>(controller: C)
-where
+///
+/// `CONNS` is the maximum number of simultaneous connections, and `CHANNELS` the maximum number of
+/// L2CAP channels, two for each connection (Signal + att). The device keeps advertising while
+/// fewer than `CONNS` centrals are connected.
+///
+/// `name` is advertised as the local name in the scan response, shortened to
+/// [`util::LOCAL_NAME_MAX_LEN`] bytes if needed. It is also used as the GAP device name, shortened
+/// to [`util::GAP_NAME_MAX_LEN`] bytes if needed. `appearance` is the GAP appearance.
+///
+/// `connection_params` are requested from every central that connects. Invalid parameters are
+/// never requested, and the connection keeps the central's parameters instead.
+pub async fn ble_bas_peripheral_run<C, const CONNS: usize, const CHANNELS: usize>(
+    controller: C,
+    name: &'static str,
+    appearance: &'static BluetoothUuid16,
+    connection_params: util::ConnectionParams,
+) where
     C: Controller,
 {
-    // Using a fixed "random" address can be useful for testing. In real scenarios, one would
-    // use e.g. the MAC 6 byte array as the address (how to get that varies by the platform).
-    let address: Address = Address::random([0xff, 0x8f, 0x1a, 0x05, 0xe4, 0xff]);
+    // The radio is initialized by now, so whatever is left is what the application can use.
+    let free_heap = esp_alloc::HEAP.free();
+    info!("Free heap after radio init: {} bytes", free_heap);
+    if free_heap < MIN_FREE_HEAP {
+        warn!(
+            "Only {} bytes of heap left, at least {} are recommended",
+            free_heap, MIN_FREE_HEAP
+        );
+    }
+
+    let connection_params = if connection_params.is_valid() {
+        Some(connection_params)
+    } else {
+        warn!(
+            "invalid connection parameters {:?}, keeping the central's",
+            connection_params
+        );
+        None
+    };
+
+    // Using a fixed "random" address can be useful for testing, see the `fixed-address` feature.
+    // Otherwise the address is derived from the chip's factory MAC, so that multiple devices don't
+    // collide. `cfg!` rather than `#[cfg]` keeps both branches building whatever the features.
+    let address: Address = if cfg!(feature = "fixed-address") {
+        Address::random([0xff, 0x8f, 0x1a, 0x05, 0xe4, 0xff])
+    } else {
+        Address::random(util::address_from_mac(Efuse::mac_address()))
+    };
     info!("Our address = {:?}", address);
 
-    let mut resources: HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX> =
-        HostResources::new();
+    let mut resources: HostResources<DefaultPacketPool, CONNS, CHANNELS> = HostResources::new();
     let stack = trouble_host::new(controller, &mut resources).set_random_address(address);
     let Host {
-        mut peripheral,
-        runner,
-        ..
+        peripheral, runner, ..
     } = stack.build();
+    let peripheral = Mutex::<NoopRawMutex, _>::new(peripheral);
+
+    // The advertising data only depends on the configuration, so it is encoded once here rather
+    // than failing the same way on every advertising attempt.
+    let local_name = util::local_name(name);
+    if matches!(local_name, AdStructure::ShortenedLocalName(_)) {
+        warn!(
+            "[adv] name is longer than {} bytes, advertising a shortened name",
+            util::LOCAL_NAME_MAX_LEN
+        );
+    }
+    let mut adv_data = [0; 31];
+    let mut scan_data = [0; 31];
+    let (adv_len, scan_len) = match util::encode_advertisement(
+        local_name,
+        &FIRMWARE_VERSION,
+        &mut adv_data,
+        &mut scan_data,
+    ) {
+        Ok(lens) => lens,
+        Err(e) => panic!("[adv] advertising data doesn't fit: {:?}", e),
+    };
+    let adv_data = &adv_data[..adv_len];
+    let scan_data = &scan_data[..scan_len];
+
+    let gap_name = util::shorten_name(name, util::GAP_NAME_MAX_LEN);
+    if gap_name.len() < name.len() {
+        warn!(
+            "name is longer than {} bytes, using {:?} as the GAP device name",
+            util::GAP_NAME_MAX_LEN,
+            gap_name
+        );
+    }
 
     info!("Starting advertising and GATT service");
     let server = Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
-        name: "TrouBLE",
-        appearance: &appearance::power_device::GENERIC_POWER_DEVICE,
+        name: gap_name,
+        appearance,
     }))
     .unwrap();
 
-    let _ = join(ble_task(runner), async {
-        loop {
-            match advertise("Trouble Example", &mut peripheral, &server).await {
-                Ok(conn) => {
-                    // set up tasks when the connection is established to a central, so they don't
-                    // run when no one is connected.
-                    let a = gatt_events_task(&server, &conn);
-                    let b = custom_task(&server, &conn, &stack);
-                    // run until any task ends (usually because the connection has been closed),
-                    // then return to advertising state.
-                    select(a, b).await;
-                }
-                Err(e) => {
-                    panic!("[adv] error: {:?}", e);
-                }
+    let _ = join(
+        ble_task(runner),
+        join_array(core::array::from_fn::<_, CONNS, _>(|_| {
+            connection_task(
+                adv_data,
+                scan_data,
+                &peripheral,
+                &server,
+                &stack,
+                connection_params,
+            )
+        })),
+    )
+    .await;
+}
+
+/// Advertise until a central connects, serve the connection until it closes, then start over.
+///
+/// One of these runs for each connection the stack allows. Only one of them advertises at a time,
+/// the others wait for the peripheral or serve their own connection.
+async fn connection_task<'values, C: Controller>(
+    adv_data: &[u8],
+    scan_data: &[u8],
+    peripheral: &Mutex<NoopRawMutex, Peripheral<'values, C, DefaultPacketPool>>,
+    server: &Server<'values>,
+    stack: &Stack<'_, C, DefaultPacketPool>,
+    connection_params: Option<util::ConnectionParams>,
+) {
+    let mut backoff_ms = util::RETRY_BACKOFF_MIN_MS;
+    loop {
+        let result = advertise(adv_data, scan_data, &mut *peripheral.lock().await, server).await;
+        match result {
+            Ok(conn) => {
+                backoff_ms = util::RETRY_BACKOFF_MIN_MS;
+                // set up tasks when the connection is established to a central, so they don't
+                // run when no one is connected.
+                let a = gatt_events_task(server, &conn);
+                let b = async {
+                    if let Some(params) = &connection_params {
+                        request_connection_params(&conn, stack, params).await;
+                    }
+                    custom_task(server, &conn, stack).await
+                };
+                // run until any task ends (usually because the connection has been closed),
+                // then return to advertising state.
+                select(a, b).await;
+            }
+            Err(e) => {
+                warn!("[adv] error: {:?}, retrying in {} ms", e, backoff_ms);
+                Timer::after_millis(backoff_ms).await;
+                backoff_ms = util::next_backoff(backoff_ms);
             }
         }
-    })
-    .await;
+    }
 }
 
 /// This is a background task that is required to run forever alongside any other BLE tasks.
@@ -129,9 +290,40 @@ where
 /// spawner.must_spawn(ble_task(runner));
 /// ```
 async fn ble_task<C: Controller, P: PacketPool>(mut runner: Runner<'_, C, P>) {
+    let mut backoff_ms = util::RETRY_BACKOFF_MIN_MS;
     loop {
+        let started = Instant::now();
         if let Err(e) = runner.run().await {
-            panic!("[ble_task] error: {:?}", e);
+            backoff_ms = util::backoff_after_run(backoff_ms, started.elapsed().as_millis());
+            warn!("[ble_task] error: {:?}, retrying in {} ms", e, backoff_ms);
+            Timer::after_millis(backoff_ms).await;
+            backoff_ms = util::next_backoff(backoff_ms);
+        }
+    }
+}
+
+/// Feed the watchdog, which resets the chip if the feeds stop for [`util::WATCHDOG_TIMEOUT_MS`].
+///
+/// This task runs on the same executor as the BLE stack, and only gets to run when the other
+/// tasks yield. If `runner.run()` in [`ble_task`] stalls without yielding, the feeds stop and the
+/// watchdog resets the chip.
+#[embassy_executor::task]
+async fn watchdog_task(mut wdt: Wdt<TIMG0<'static>>) {
+    wdt.set_timeout(
+        MwdtStage::Stage0,
+        esp_hal::time::Duration::from_millis(util::WATCHDOG_TIMEOUT_MS),
+    );
+    wdt.enable();
+    let mut feeds = util::WatchdogFeeds::new(util::WATCHDOG_TIMEOUT_MS, Instant::now().as_millis());
+    loop {
+        Timer::after_millis(util::WATCHDOG_FEED_INTERVAL_MS).await;
+        wdt.feed();
+        let remaining_ms = feeds.feed(Instant::now().as_millis());
+        if remaining_ms < util::WATCHDOG_TIMEOUT_MS / 2 {
+            warn!(
+                "[watchdog] fed late, {} ms before the watchdog would have reset",
+                remaining_ms
+            );
         }
     }
 }
@@ -176,31 +368,36 @@ async fn gatt_events_task<P: PacketPool>(
             _ => {} // ignore other Gatt Connection Events
         }
     };
-    info!("[gatt] disconnected: {:?}", reason);
+    let code = reason.into_inner();
+    if util::is_link_loss(code) {
+        warn!(
+            "[gatt] connection lost: {} ({:?})",
+            util::disconnect_reason(code),
+            reason
+        );
+    } else {
+        info!(
+            "[gatt] disconnected: {} ({:?})",
+            util::disconnect_reason(code),
+            reason
+        );
+    }
     Ok(())
 }
 
 /// Create an advertiser to use to connect to a BLE Central, and wait for it to connect.
 async fn advertise<'values, 'server, C: Controller>(
-    name: &'values str,
+    adv_data: &[u8],
+    scan_data: &[u8],
     peripheral: &mut Peripheral<'values, C, DefaultPacketPool>,
     server: &'server Server<'values>,
 ) -> Result<GattConnection<'values, 'server, DefaultPacketPool>, BleHostError<C::Error>> {
-    let mut advertiser_data = [0; 31];
-    let len = AdStructure::encode_slice(
-        &[
-            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-            AdStructure::ServiceUuids16(&[[0x0f, 0x18]]),
-            AdStructure::CompleteLocalName(name.as_bytes()),
-        ],
-        &mut advertiser_data[..],
-    )?;
     let advertiser = peripheral
         .advertise(
             &Default::default(),
             Advertisement::ConnectableScannableUndirected {
-                adv_data: &advertiser_data[..len],
-                scan_data: &[],
+                adv_data,
+                scan_data,
             },
         )
         .await?;
@@ -210,6 +407,37 @@ async fn advertise<'values, 'server, C: Controller>(
     Ok(conn)
 }
 
+/// Ask the central for `params`, once per connection.
+///
+/// The central may reject them, in which case the connection keeps the parameters the central
+/// chose. The ATT MTU isn't requested here: the GATT client starts that exchange, and trouble-host
+/// answers it with the largest MTU the packet pool supports, 255 bytes with the
+/// `default-packet-pool-mtu-255` feature.
+async fn request_connection_params<C: Controller, P: PacketPool>(
+    conn: &GattConnection<'_, '_, P>,
+    stack: &Stack<'_, C, P>,
+    params: &util::ConnectionParams,
+) {
+    let connect_params = ConnectParams {
+        min_connection_interval: Duration::from_micros(params.min_interval_us.into()),
+        max_connection_interval: Duration::from_micros(params.max_interval_us.into()),
+        max_latency: params.max_latency,
+        supervision_timeout: Duration::from_millis(params.supervision_timeout_ms.into()),
+        ..Default::default()
+    };
+    match conn
+        .raw()
+        .update_connection_params(stack, &connect_params)
+        .await
+    {
+        Ok(()) => info!("[conn] requested connection parameters {:?}", params),
+        Err(e) => warn!(
+            "[conn] connection parameters rejected: {:?}, keeping the central's",
+            e
+        ),
+    }
+}
+
 /// Example task to use the BLE notifier interface.
 /// This task will notify the connected central of a counter value every 2 seconds.
 /// It will also read the RSSI value every 2 seconds.
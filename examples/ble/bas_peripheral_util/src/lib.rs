@@ -0,0 +1,672 @@
+//! Hardware independent helpers of the `bas_peripheral` BLE example.
+//!
+//! They live in their own crate so that they can be tested on the host, by running `cargo test`
+//! in this directory.
+
+#![cfg_attr(not(test), no_std)]
+
+use trouble_host::prelude::{AdStructure, BR_EDR_NOT_SUPPORTED, Error, LE_GENERAL_DISCOVERABLE};
+
+/// Derive a static random BLE address from a MAC address.
+///
+/// The address is stored little-endian, and a static random address requires the two most
+/// significant bits to be set.
+pub fn address_from_mac(mac: [u8; 6]) -> [u8; 6] {
+    let mut address = mac;
+    address.reverse();
+    address[5] |= 0xc0;
+    address
+}
+
+/// Delay before the first retry after a BLE error, in milliseconds.
+pub const RETRY_BACKOFF_MIN_MS: u64 = 100;
+/// Upper bound for the delay between retries, in milliseconds.
+pub const RETRY_BACKOFF_MAX_MS: u64 = 10_000;
+/// How long the BLE runner has to run without an error before the retry delay starts over, in
+/// milliseconds.
+pub const RETRY_BACKOFF_RESET_MS: u64 = 60_000;
+
+/// Double the retry delay, capped at [`RETRY_BACKOFF_MAX_MS`].
+pub fn next_backoff(backoff_ms: u64) -> u64 {
+    (backoff_ms * 2).min(RETRY_BACKOFF_MAX_MS)
+}
+
+/// Retry delay to use after the BLE runner failed having run for `ran_for_ms` milliseconds.
+///
+/// A runner that ran for at least [`RETRY_BACKOFF_RESET_MS`] has recovered from any earlier error,
+/// so the delay starts over at [`RETRY_BACKOFF_MIN_MS`].
+pub fn backoff_after_run(backoff_ms: u64, ran_for_ms: u64) -> u64 {
+    if ran_for_ms >= RETRY_BACKOFF_RESET_MS {
+        RETRY_BACKOFF_MIN_MS
+    } else {
+        backoff_ms
+    }
+}
+
+/// Shortest connection interval the Bluetooth Core specification allows, in microseconds.
+pub const CONNECTION_INTERVAL_MIN_US: u32 = 7_500;
+/// Longest connection interval the Bluetooth Core specification allows, in microseconds.
+pub const CONNECTION_INTERVAL_MAX_US: u32 = 4_000_000;
+/// Connection intervals are multiples of this, in microseconds.
+pub const CONNECTION_INTERVAL_STEP_US: u32 = 1_250;
+/// Largest peripheral latency the Bluetooth Core specification allows, in connection events.
+pub const MAX_LATENCY_MAX: u16 = 499;
+/// Shortest supervision timeout the Bluetooth Core specification allows, in milliseconds.
+pub const SUPERVISION_TIMEOUT_MIN_MS: u32 = 100;
+/// Longest supervision timeout the Bluetooth Core specification allows, in milliseconds.
+pub const SUPERVISION_TIMEOUT_MAX_MS: u32 = 32_000;
+/// Supervision timeouts are multiples of this, in milliseconds.
+pub const SUPERVISION_TIMEOUT_STEP_MS: u32 = 10;
+
+/// Connection parameters to request from the central once connected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionParams {
+    /// Shortest connection interval to ask for, in microseconds.
+    pub min_interval_us: u32,
+    /// Longest connection interval to ask for, in microseconds.
+    pub max_interval_us: u32,
+    /// Number of connection events the peripheral may skip when it has nothing to send.
+    pub max_latency: u16,
+    /// Time without hearing from the central after which the connection is considered lost, in
+    /// milliseconds.
+    pub supervision_timeout_ms: u32,
+}
+
+impl ConnectionParams {
+    /// Whether the parameters are within the ranges of the Bluetooth Core specification, so that a
+    /// central can accept them.
+    ///
+    /// The supervision timeout also has to be longer than two of the longest intervals between
+    /// connection events the peripheral listens to, so that one missed event doesn't drop the
+    /// connection.
+    pub const fn is_valid(&self) -> bool {
+        let listen_interval_us = (1 + self.max_latency as u64) * self.max_interval_us as u64;
+        CONNECTION_INTERVAL_MIN_US <= self.min_interval_us
+            && self.min_interval_us <= self.max_interval_us
+            && self.max_interval_us <= CONNECTION_INTERVAL_MAX_US
+            && self
+                .min_interval_us
+                .is_multiple_of(CONNECTION_INTERVAL_STEP_US)
+            && self
+                .max_interval_us
+                .is_multiple_of(CONNECTION_INTERVAL_STEP_US)
+            && self.max_latency <= MAX_LATENCY_MAX
+            && SUPERVISION_TIMEOUT_MIN_MS <= self.supervision_timeout_ms
+            && self.supervision_timeout_ms <= SUPERVISION_TIMEOUT_MAX_MS
+            && self
+                .supervision_timeout_ms
+                .is_multiple_of(SUPERVISION_TIMEOUT_STEP_MS)
+            && self.supervision_timeout_ms as u64 * 1_000 > 2 * listen_interval_us
+    }
+}
+
+/// Describe the HCI error code a connection was closed with.
+pub fn disconnect_reason(code: u8) -> &'static str {
+    match code {
+        0x05 => "authentication failure",
+        0x08 => "supervision timeout",
+        0x13 => "closed by the central",
+        0x14 => "closed by the central, low resources",
+        0x15 => "closed by the central, powering off",
+        0x16 => "closed by the peripheral",
+        0x1a => "unsupported remote feature",
+        0x22 => "link layer response timeout",
+        0x3b => "unacceptable connection parameters",
+        0x3d => "message integrity check failure",
+        0x3e => "connection failed to be established",
+        _ => "unknown reason",
+    }
+}
+
+/// Whether the HCI error code a connection was closed with means the link was lost, e.g. because
+/// the central went out of range, rather than closed by either side.
+pub fn is_link_loss(code: u8) -> bool {
+    matches!(code, 0x08 | 0x22 | 0x3e)
+}
+
+/// Longest GAP device name trouble-host accepts, in bytes.
+pub const GAP_NAME_MAX_LEN: usize = 22;
+
+/// Shorten `name` to at most `max_len` bytes, without splitting a character.
+pub fn shorten_name(name: &str, max_len: usize) -> &str {
+    if name.len() <= max_len {
+        return name;
+    }
+    let mut end = max_len;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// Longest local name that fits the scan response data, in bytes.
+///
+/// The name's own header takes 2 of the 31 bytes.
+pub const LOCAL_NAME_MAX_LEN: usize = 31 - 2;
+
+/// The local name AD structure for `name`.
+///
+/// Names longer than [`LOCAL_NAME_MAX_LEN`] bytes are shortened, without splitting a character,
+/// and advertised as a shortened local name.
+pub fn local_name(name: &str) -> AdStructure<'_> {
+    let shortened = shorten_name(name, LOCAL_NAME_MAX_LEN);
+    if shortened.len() < name.len() {
+        AdStructure::ShortenedLocalName(shortened.as_bytes())
+    } else {
+        AdStructure::CompleteLocalName(name.as_bytes())
+    }
+}
+
+/// Encode AD structures into the advertising data and the scan response data.
+///
+/// `adv_structures` go into the advertising data, in order. Once one of them doesn't fit, it and
+/// all the ones after it go into the scan response data instead, ahead of `scan_structures`.
+/// Returns the number of bytes used in each buffer.
+pub fn encode_ad_structures(
+    adv_structures: &[AdStructure<'_>],
+    scan_structures: &[AdStructure<'_>],
+    adv_data: &mut [u8],
+    scan_data: &mut [u8],
+) -> Result<(usize, usize), Error> {
+    let mut adv_len = 0;
+    let mut spilled = adv_structures.len();
+    for (i, structure) in adv_structures.iter().enumerate() {
+        let structure = core::slice::from_ref(structure);
+        match AdStructure::encode_slice(structure, &mut adv_data[adv_len..]) {
+            Ok(len) => adv_len += len,
+            Err(_) => {
+                spilled = i;
+                break;
+            }
+        }
+    }
+
+    let mut scan_len = 0;
+    for structure in adv_structures[spilled..].iter().chain(scan_structures) {
+        let structure = core::slice::from_ref(structure);
+        scan_len += AdStructure::encode_slice(structure, &mut scan_data[scan_len..])?;
+    }
+    Ok((adv_len, scan_len))
+}
+
+/// Company identifier of the manufacturer specific data that carries the firmware version.
+///
+/// 0xFFFF is reserved by the Bluetooth SIG for tests. A product has to use its own company
+/// identifier instead.
+pub const COMPANY_ID: u16 = 0xffff;
+
+/// Parse a decimal version component, such as `CARGO_PKG_VERSION_MAJOR`, into a byte.
+pub const fn version_component(component: &str) -> u8 {
+    let digits = component.as_bytes();
+    assert!(!digits.is_empty(), "version component must not be empty");
+    let mut value: u8 = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        assert!(
+            digits[i].is_ascii_digit(),
+            "version component must be a decimal number"
+        );
+        value = match value.checked_mul(10) {
+            Some(value) => value,
+            None => panic!("version component must fit in a byte"),
+        };
+        value = match value.checked_add(digits[i] - b'0') {
+            Some(value) => value,
+            None => panic!("version component must fit in a byte"),
+        };
+        i += 1;
+    }
+    value
+}
+
+/// Encode the advertising and scan response data of the example.
+///
+/// The advertising data carries the flags, the Battery Service UUID and the firmware version as
+/// manufacturer specific data. The local name goes into the scan response, which keeps the
+/// advertising data small. Returns the number of bytes used in each buffer.
+pub fn encode_advertisement(
+    local_name: AdStructure<'_>,
+    firmware_version: &[u8; 3],
+    adv_data: &mut [u8],
+    scan_data: &mut [u8],
+) -> Result<(usize, usize), Error> {
+    encode_ad_structures(
+        &[
+            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+            AdStructure::ServiceUuids16(&[[0x0f, 0x18]]),
+            AdStructure::ManufacturerSpecificData {
+                company_identifier: COMPANY_ID,
+                payload: firmware_version,
+            },
+        ],
+        &[local_name],
+        adv_data,
+        scan_data,
+    )
+}
+
+/// Time without a feed after which the watchdog resets the chip, in milliseconds.
+pub const WATCHDOG_TIMEOUT_MS: u64 = 5_000;
+/// How often the watchdog is fed, in milliseconds.
+///
+/// Feeding four times per timeout leaves room for a few late feeds before the chip resets.
+pub const WATCHDOG_FEED_INTERVAL_MS: u64 = WATCHDOG_TIMEOUT_MS / 4;
+
+/// Bookkeeping of the watchdog feeds, to notice late ones before the watchdog resets the chip.
+pub struct WatchdogFeeds {
+    timeout_ms: u64,
+    last_feed_ms: u64,
+}
+
+impl WatchdogFeeds {
+    /// Start the bookkeeping for a watchdog that resets after `timeout_ms`, fed at `now_ms`.
+    pub const fn new(timeout_ms: u64, now_ms: u64) -> Self {
+        Self {
+            timeout_ms,
+            last_feed_ms: now_ms,
+        }
+    }
+
+    /// Time left at `now_ms` before the watchdog resets the chip, in milliseconds.
+    pub const fn remaining_ms(&self, now_ms: u64) -> u64 {
+        self.timeout_ms
+            .saturating_sub(now_ms.saturating_sub(self.last_feed_ms))
+    }
+
+    /// Record a feed at `now_ms`, returning the time that was left before it, in milliseconds.
+    pub fn feed(&mut self, now_ms: u64) -> u64 {
+        let remaining_ms = self.remaining_ms(now_ms);
+        self.last_feed_ms = now_ms;
+        remaining_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x10, 0x20, 0x30, 0x40, 0x50, 0x60];
+
+    #[test]
+    fn address_from_mac_is_deterministic() {
+        assert_eq!(address_from_mac(MAC), address_from_mac(MAC));
+        assert_eq!(address_from_mac(MAC), [0x60, 0x50, 0x40, 0x30, 0x20, 0xd0]);
+    }
+
+    #[test]
+    fn address_from_mac_is_static_random() {
+        for mac in [[0; 6], [0xff; 6], MAC] {
+            assert_eq!(address_from_mac(mac)[5] & 0xc0, 0xc0);
+        }
+    }
+
+    #[test]
+    fn address_from_mac_differs_for_different_macs() {
+        let other = [0x10, 0x20, 0x30, 0x40, 0x50, 0x61];
+        assert_ne!(address_from_mac(MAC), address_from_mac(other));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff_ms = RETRY_BACKOFF_MIN_MS;
+        let mut schedule = [0; 9];
+        for delay in schedule.iter_mut() {
+            *delay = backoff_ms;
+            backoff_ms = next_backoff(backoff_ms);
+        }
+        assert_eq!(
+            schedule,
+            [100, 200, 400, 800, 1_600, 3_200, 6_400, 10_000, 10_000]
+        );
+    }
+
+    #[test]
+    fn backoff_resets_after_a_long_run() {
+        assert_eq!(
+            backoff_after_run(RETRY_BACKOFF_MAX_MS, 0),
+            RETRY_BACKOFF_MAX_MS
+        );
+        assert_eq!(
+            backoff_after_run(RETRY_BACKOFF_MAX_MS, RETRY_BACKOFF_RESET_MS - 1),
+            RETRY_BACKOFF_MAX_MS
+        );
+        assert_eq!(
+            backoff_after_run(RETRY_BACKOFF_MAX_MS, RETRY_BACKOFF_RESET_MS),
+            RETRY_BACKOFF_MIN_MS
+        );
+    }
+
+    const PARAMS: ConnectionParams = ConnectionParams {
+        min_interval_us: 7_500,
+        max_interval_us: 15_000,
+        max_latency: 0,
+        supervision_timeout_ms: 4_000,
+    };
+
+    #[test]
+    fn connection_params_within_the_specification_are_valid() {
+        assert!(PARAMS.is_valid());
+        assert!(
+            ConnectionParams {
+                min_interval_us: CONNECTION_INTERVAL_MAX_US,
+                max_interval_us: CONNECTION_INTERVAL_MAX_US,
+                max_latency: 2,
+                supervision_timeout_ms: SUPERVISION_TIMEOUT_MAX_MS,
+            }
+            .is_valid()
+        );
+        assert!(
+            ConnectionParams {
+                max_latency: MAX_LATENCY_MAX,
+                supervision_timeout_ms: SUPERVISION_TIMEOUT_MAX_MS,
+                ..PARAMS
+            }
+            .is_valid()
+        );
+    }
+
+    #[test]
+    fn connection_params_out_of_range_are_invalid() {
+        let too_short = ConnectionParams {
+            min_interval_us: 6_250,
+            ..PARAMS
+        };
+        let too_long = ConnectionParams {
+            max_interval_us: CONNECTION_INTERVAL_MAX_US + CONNECTION_INTERVAL_STEP_US,
+            ..PARAMS
+        };
+        let too_much_latency = ConnectionParams {
+            max_latency: MAX_LATENCY_MAX + 1,
+            ..PARAMS
+        };
+        let timeout_too_short = ConnectionParams {
+            supervision_timeout_ms: SUPERVISION_TIMEOUT_MIN_MS - SUPERVISION_TIMEOUT_STEP_MS,
+            ..PARAMS
+        };
+        let timeout_too_long = ConnectionParams {
+            supervision_timeout_ms: SUPERVISION_TIMEOUT_MAX_MS + SUPERVISION_TIMEOUT_STEP_MS,
+            ..PARAMS
+        };
+        for params in [
+            too_short,
+            too_long,
+            too_much_latency,
+            timeout_too_short,
+            timeout_too_long,
+        ] {
+            assert!(!params.is_valid(), "{params:?}");
+        }
+    }
+
+    #[test]
+    fn connection_params_must_be_ordered_and_on_the_interval_step() {
+        let reversed = ConnectionParams {
+            min_interval_us: 15_000,
+            max_interval_us: 7_500,
+            ..PARAMS
+        };
+        let off_step = ConnectionParams {
+            max_interval_us: 10_500,
+            ..PARAMS
+        };
+        let timeout_off_step = ConnectionParams {
+            supervision_timeout_ms: 4_005,
+            ..PARAMS
+        };
+        assert!(!reversed.is_valid());
+        assert!(!off_step.is_valid());
+        assert!(!timeout_off_step.is_valid());
+    }
+
+    #[test]
+    fn supervision_timeout_must_outlast_two_listen_intervals() {
+        // With a latency of 4, the peripheral listens every 5 * 15 ms = 75 ms.
+        let params = ConnectionParams {
+            max_latency: 4,
+            supervision_timeout_ms: 160,
+            ..PARAMS
+        };
+        assert!(params.is_valid());
+        assert!(
+            !ConnectionParams {
+                supervision_timeout_ms: 150,
+                ..params
+            }
+            .is_valid()
+        );
+    }
+
+    #[test]
+    fn disconnect_reasons_are_decoded() {
+        for (code, reason, link_loss) in [
+            (0x05, "authentication failure", false),
+            (0x08, "supervision timeout", true),
+            (0x13, "closed by the central", false),
+            (0x14, "closed by the central, low resources", false),
+            (0x15, "closed by the central, powering off", false),
+            (0x16, "closed by the peripheral", false),
+            (0x1a, "unsupported remote feature", false),
+            (0x22, "link layer response timeout", true),
+            (0x3b, "unacceptable connection parameters", false),
+            (0x3d, "message integrity check failure", false),
+            (0x3e, "connection failed to be established", true),
+            (0x00, "unknown reason", false),
+            (0xff, "unknown reason", false),
+        ] {
+            assert_eq!(disconnect_reason(code), reason, "{code:#04x}");
+            assert_eq!(is_link_loss(code), link_loss, "{code:#04x}");
+        }
+    }
+
+    #[test]
+    fn shorten_name_keeps_short_names() {
+        assert_eq!(
+            shorten_name("Trouble Example", GAP_NAME_MAX_LEN),
+            "Trouble Example"
+        );
+        assert_eq!(shorten_name("", GAP_NAME_MAX_LEN), "");
+    }
+
+    #[test]
+    fn shorten_name_cuts_long_names_deterministically() {
+        let name = "A rather long device name for the example";
+        let shortened = shorten_name(name, GAP_NAME_MAX_LEN);
+        assert_eq!(shortened, "A rather long device n");
+        assert_eq!(shortened, shorten_name(name, GAP_NAME_MAX_LEN));
+    }
+
+    #[test]
+    fn shorten_name_does_not_split_characters() {
+        // "é" takes two bytes, and the limit falls between them.
+        let name = "Progressor élite";
+        assert_eq!(shorten_name(name, 12), "Progressor ");
+        assert_eq!(shorten_name(name, 13), "Progressor é");
+    }
+
+    const FLAGS: AdStructure<'static> =
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED);
+
+    #[test]
+    fn structures_that_fit_stay_in_the_advertising_data() {
+        // 3 bytes of flags and 28 bytes of service data fill the 31 bytes exactly.
+        let (mut adv_data, mut scan_data) = ([0; 31], [0; 31]);
+        let lens = encode_ad_structures(
+            &[
+                FLAGS,
+                AdStructure::ServiceData16 {
+                    uuid: [0x0f, 0x18],
+                    data: &[0; 24],
+                },
+            ],
+            &[],
+            &mut adv_data,
+            &mut scan_data,
+        );
+        assert_eq!(lens.ok(), Some((31, 0)));
+    }
+
+    #[test]
+    fn structures_that_overflow_spill_in_order() {
+        // The service data is a byte too long for the advertising data, so it goes to the scan
+        // response with the UUIDs after it, even though the UUIDs alone would still fit.
+        let (mut adv_data, mut scan_data) = ([0; 31], [0; 31]);
+        let lens = encode_ad_structures(
+            &[
+                FLAGS,
+                AdStructure::ServiceUuids16(&[[0x0f, 0x18], [0x0a, 0x18]]),
+                AdStructure::ServiceData16 {
+                    uuid: [0x0f, 0x18],
+                    data: &[0; 19],
+                },
+                AdStructure::ServiceUuids16(&[[0x0f, 0x18]]),
+            ],
+            &[AdStructure::CompleteLocalName(b"n")],
+            &mut adv_data,
+            &mut scan_data,
+        );
+        assert_eq!(lens.ok(), Some((3 + 6, 23 + 4 + 3)));
+        assert_eq!(scan_data[1], 0x16);
+        assert_eq!(scan_data[24], 0x03);
+        assert_eq!(scan_data[28], 0x09);
+    }
+
+    #[test]
+    fn structures_that_overflow_the_scan_response_are_an_error() {
+        let service_data = || AdStructure::ServiceData16 {
+            uuid: [0x0f, 0x18],
+            data: &[0; 24],
+        };
+        let (mut adv_data, mut scan_data) = ([0; 31], [0; 31]);
+        let lens = encode_ad_structures(
+            &[FLAGS, service_data(), service_data()],
+            &[service_data()],
+            &mut adv_data,
+            &mut scan_data,
+        );
+        assert!(lens.is_err());
+    }
+
+    #[test]
+    fn longest_local_name_fills_the_scan_response() {
+        let name = "n".repeat(LOCAL_NAME_MAX_LEN);
+        let (mut adv_data, mut scan_data) = ([0; 31], [0; 31]);
+        let lens = encode_advertisement(
+            AdStructure::CompleteLocalName(name.as_bytes()),
+            &[1, 2, 3],
+            &mut adv_data,
+            &mut scan_data,
+        );
+        assert_eq!(lens.ok(), Some((3 + 4 + 7, 31)));
+
+        let name = "n".repeat(LOCAL_NAME_MAX_LEN + 1);
+        let lens = encode_advertisement(
+            AdStructure::CompleteLocalName(name.as_bytes()),
+            &[1, 2, 3],
+            &mut adv_data,
+            &mut scan_data,
+        );
+        assert!(lens.is_err());
+    }
+
+    #[test]
+    fn watchdog_fed_on_time_keeps_most_of_the_timeout() {
+        let mut feeds = WatchdogFeeds::new(WATCHDOG_TIMEOUT_MS, 0);
+        for i in 1..=10 {
+            let remaining_ms = feeds.feed(i * WATCHDOG_FEED_INTERVAL_MS);
+            assert_eq!(
+                remaining_ms,
+                WATCHDOG_TIMEOUT_MS - WATCHDOG_FEED_INTERVAL_MS
+            );
+        }
+    }
+
+    #[test]
+    fn watchdog_fed_late_reports_the_time_left() {
+        let mut feeds = WatchdogFeeds::new(WATCHDOG_TIMEOUT_MS, 1_000);
+        assert_eq!(feeds.remaining_ms(1_000), WATCHDOG_TIMEOUT_MS);
+        assert_eq!(feeds.feed(1_000 + 4_000), 1_000);
+        // The late feed restarts the count.
+        assert_eq!(feeds.remaining_ms(5_000), WATCHDOG_TIMEOUT_MS);
+    }
+
+    #[test]
+    fn watchdog_stalled_past_the_timeout_has_no_time_left() {
+        let feeds = WatchdogFeeds::new(WATCHDOG_TIMEOUT_MS, 0);
+        assert_eq!(feeds.remaining_ms(WATCHDOG_TIMEOUT_MS), 0);
+        assert_eq!(feeds.remaining_ms(10 * WATCHDOG_TIMEOUT_MS), 0);
+    }
+
+    #[test]
+    fn version_components_are_parsed() {
+        assert_eq!(version_component("0"), 0);
+        assert_eq!(version_component("7"), 7);
+        assert_eq!(version_component("42"), 42);
+        assert_eq!(version_component("255"), 255);
+    }
+
+    #[test]
+    #[should_panic(expected = "version component must fit in a byte")]
+    fn version_components_over_a_byte_are_rejected() {
+        version_component("256");
+    }
+
+    #[test]
+    #[should_panic(expected = "version component must be a decimal number")]
+    fn version_components_that_are_not_numbers_are_rejected() {
+        version_component("1-rc");
+    }
+
+    #[test]
+    fn firmware_version_is_encoded_as_manufacturer_data() {
+        let (mut adv_data, mut scan_data) = ([0; 31], [0; 31]);
+        let (adv_len, scan_len) = encode_advertisement(
+            AdStructure::CompleteLocalName(b"Trouble Example"),
+            &[1, 2, 3],
+            &mut adv_data,
+            &mut scan_data,
+        )
+        .unwrap();
+        // Flags, Battery Service UUID, then length, type, company identifier and version.
+        assert_eq!(adv_len, 3 + 4 + 7);
+        assert_eq!(&adv_data[7..14], &[6, 0xff, 0xff, 0xff, 1, 2, 3]);
+        assert_eq!(scan_len, 2 + 15);
+    }
+
+    #[test]
+    fn short_names_are_complete_local_names() {
+        let name = "n".repeat(LOCAL_NAME_MAX_LEN);
+        match local_name(&name) {
+            AdStructure::CompleteLocalName(encoded) => assert_eq!(encoded, name.as_bytes()),
+            _ => panic!("expected a complete local name"),
+        }
+    }
+
+    #[test]
+    fn long_names_are_shortened_to_fit() {
+        let name = "n".repeat(LOCAL_NAME_MAX_LEN + 10);
+        match local_name(&name) {
+            AdStructure::ShortenedLocalName(encoded) => {
+                assert_eq!(encoded, &name.as_bytes()[..LOCAL_NAME_MAX_LEN]);
+            }
+            _ => panic!("expected a shortened local name"),
+        }
+
+        let (mut adv_data, mut scan_data) = ([0; 31], [0; 31]);
+        let lens =
+            encode_advertisement(local_name(&name), &[1, 2, 3], &mut adv_data, &mut scan_data);
+        assert_eq!(lens.ok(), Some((3 + 4 + 7, 31)));
+        assert_eq!(scan_data[1], 0x08);
+    }
+
+    #[test]
+    fn long_names_are_shortened_on_a_character_boundary() {
+        // Each "é" takes two bytes, so 29 bytes would end in the middle of one.
+        let name = "é".repeat(20);
+        match local_name(&name) {
+            AdStructure::ShortenedLocalName(encoded) => {
+                assert_eq!(encoded, "é".repeat(14).as_bytes());
+            }
+            _ => panic!("expected a shortened local name"),
+        }
+    }
+}